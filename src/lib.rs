@@ -0,0 +1,6 @@
+//! Library crate exposing the ping primitives shared by the synchronous
+//! (`main`) and asynchronous (`async`) binaries.
+
+pub mod icmp;
+pub mod parser;
+pub mod pinger;
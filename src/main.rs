@@ -4,7 +4,10 @@
 
 use std::{
     collections::HashMap,
-    sync::{Arc, RwLock},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
     time::Duration,
 };
 
@@ -32,9 +35,23 @@ fn ping(hosts: Vec<PingParams>) -> PingResult<()> {
     tx.set_ttl(64)?;
     let mut iter = ipv4_packet_iter(&mut rx);
 
+    // On the first SIGINT/SIGTERM, stop scheduling new echoes but let the
+    // event loop drain whatever's still outstanding (up to its timeout)
+    // before printing a summary -- a partial sweep still reports everything
+    // it learned instead of being killed mid-loop.
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let handler_flag = shutdown.clone();
+    ctrlc::set_handler(move || {
+        handler_flag.store(true, Ordering::SeqCst);
+    })
+    .expect("failed to install SIGINT/SIGTERM handler");
+
     for host in hosts {
-        if let Err(e) =
-            Pinger::new(&host, Duration::from_secs(5), map.clone()).ping(&mut tx, &mut iter)
+        if shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        if let Err(e) = Pinger::new(&host, Duration::from_secs(5), map.clone(), shutdown.clone())
+            .ping(&mut tx, &mut iter)
         {
             eprintln!("Failed to ping {}: {:?}", host.ip, e);
         }
@@ -3,7 +3,7 @@
 use std::{
     collections::HashMap,
     io::{stdin, Error, ErrorKind},
-    net::Ipv4Addr,
+    net::IpAddr,
 };
 
 use crate::pinger::PingParams;
@@ -33,8 +33,9 @@ pub fn parse_input() -> Vec<PingParams> {
                     }
 
                     // masking the actual errors here...ok for now
+                    // accepts either a dotted-quad IPv4 address or an IPv6 address
                     let ip = inputs[0]
-                        .parse::<Ipv4Addr>()
+                        .parse::<IpAddr>()
                         .map_err(|_| (idx, Error::from(ErrorKind::InvalidInput)))?;
                     let requests = inputs[1]
                         .parse::<u16>()
@@ -73,10 +74,7 @@ pub fn parse_input() -> Vec<PingParams> {
 
 /// Checks if there's any duplicate IPs in the parsed data. Panics if there are.
 fn check_duplicate_ips(inp: &Vec<PingParams>) {
-    let map = inp
-        .iter()
-        .map(|x| (x.ip, x))
-        .collect::<HashMap<_, _>>();
+    let map = inp.iter().map(|x| (x.ip, x)).collect::<HashMap<_, _>>();
     if map.len() != inp.len() {
         panic!("Duplicate IPs in input");
     }
@@ -4,29 +4,43 @@ use std::{
     cmp::Reverse,
     collections::{BinaryHeap, HashMap, HashSet},
     io,
-    net::{IpAddr, Ipv4Addr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
     process::id,
-    sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
     time::{Duration, Instant},
 };
 
 use pnet::{
     packet::{
         icmp::echo_reply::EchoReplyPacket,
+        icmpv6::{echo_reply::EchoReplyPacket as EchoReplyPacketV6, Icmpv6Packet, Icmpv6Types},
         ip::IpNextHeaderProtocol,
         ipv4::{Ipv4, MutableIpv4Packet},
         Packet,
     },
-    transport::{Ipv4TransportChannelIterator, TransportSender},
+    transport::{
+        icmpv6_packet_iter, transport_channel, Ipv4TransportChannelIterator, TransportChannelType,
+        TransportSender,
+    },
 };
+use rand::RngCore;
 
 use crate::icmp::IcmpEcho;
 
+/// Length in bytes of the send-timestamp prefix embedded in each echo
+/// request's payload.
+pub const PAYLOAD_TIMESTAMP_LEN: usize = 8;
+/// Length in bytes of the random nonce that follows the timestamp.
+pub const PAYLOAD_NONCE_LEN: usize = 8;
+
 /// The parameters for the pinging program
 #[derive(Debug, Clone)]
 pub struct PingParams {
-    /// address to ping
-    pub ip: Ipv4Addr,
+    /// address to ping, either IPv4 or IPv6
+    pub ip: IpAddr,
     /// number of requests to send
     pub requests: u16,
     /// interval between send requests
@@ -59,14 +73,144 @@ impl<'a, T> From<PoisonError<RwLockWriteGuard<'a, T>>> for PingError {
 
 pub type PingResult<T> = Result<T, PingError>;
 
-fn construct_icmp_echo_request(buf: &mut [u8], seq: u16, id: u16) {
-    let echo = IcmpEcho::new(id, seq);
-    echo.construct_buf(buf);
+/// A request that's been sent and is awaiting a reply: when it was sent (for
+/// latency/timeout bookkeeping) and the nonce its payload carried, so a
+/// reply can be validated against what was actually sent rather than
+/// trusted on the basis of matching IP and sequence number alone.
+#[derive(Debug, Clone, Copy)]
+pub struct SentRequest {
+    pub time: Instant,
+    pub nonce: [u8; PAYLOAD_NONCE_LEN],
 }
 
-type ActiveRequestMap = HashMap<(Ipv4Addr, u16), Instant>;
+type ActiveRequestMap = HashMap<(IpAddr, u16), SentRequest>;
 type SafeActiveRequestMap = Arc<RwLock<ActiveRequestMap>>;
 
+/// Builds the payload embedded in an echo request: an 8-byte big-endian
+/// monotonic timestamp (nanoseconds since `epoch`) followed by a random
+/// nonce, mirroring what real ping implementations embed to make latency
+/// self-describing and replies detectably spoofable/stale.
+pub fn build_payload(epoch: Instant) -> (Vec<u8>, [u8; PAYLOAD_NONCE_LEN]) {
+    let mut nonce = [0u8; PAYLOAD_NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let mut payload = Vec::with_capacity(PAYLOAD_TIMESTAMP_LEN + PAYLOAD_NONCE_LEN);
+    payload.extend_from_slice(&(epoch.elapsed().as_nanos() as u64).to_be_bytes());
+    payload.extend_from_slice(&nonce);
+    (payload, nonce)
+}
+
+/// Returns `true` if `reply_payload` carries the nonce we embedded in the
+/// matching request.
+pub fn nonce_matches(reply_payload: &[u8], nonce: &[u8; PAYLOAD_NONCE_LEN]) -> bool {
+    reply_payload.len() >= PAYLOAD_TIMESTAMP_LEN + PAYLOAD_NONCE_LEN
+        && &reply_payload[PAYLOAD_TIMESTAMP_LEN..PAYLOAD_TIMESTAMP_LEN + PAYLOAD_NONCE_LEN] == nonce
+}
+
+/// Summary statistics for a single host's sweep, accumulated as replies
+/// arrive rather than computed from a stored sample buffer: `rtt_sum_us`/
+/// `rtt_sum_sq_us` are enough to derive the average and mean deviation once
+/// the sweep finishes.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PingStats {
+    pub transmitted: u32,
+    pub received: u32,
+    /// Receive errors seen during the sweep. These never abort the event
+    /// loop -- a failed socket read is logged and skipped, not treated as
+    /// fatal. Packets that don't parse as an echo reply are skipped without
+    /// being counted here.
+    pub recv_errors: u32,
+    rtt_min_us: Option<u64>,
+    rtt_max_us: Option<u64>,
+    rtt_sum_us: f64,
+    rtt_sum_sq_us: f64,
+}
+
+impl PingStats {
+    pub fn record_sent(&mut self) {
+        self.transmitted += 1;
+    }
+
+    pub fn record_recv_error(&mut self) {
+        self.recv_errors += 1;
+    }
+
+    pub fn record_reply(&mut self, rtt: Duration) {
+        let us = rtt.as_micros() as u64;
+        self.received += 1;
+        self.rtt_sum_us += us as f64;
+        self.rtt_sum_sq_us += (us as f64) * (us as f64);
+        self.rtt_min_us = Some(self.rtt_min_us.map_or(us, |m| m.min(us)));
+        self.rtt_max_us = Some(self.rtt_max_us.map_or(us, |m| m.max(us)));
+    }
+
+    /// Percentage of transmitted requests that never received a reply.
+    pub fn loss_pct(&self) -> f64 {
+        if self.transmitted == 0 {
+            return 0.0;
+        }
+        (1.0 - self.received as f64 / self.transmitted as f64) * 100.0
+    }
+
+    /// Mean round-trip time in microseconds, or `None` if nothing was received.
+    pub fn rtt_avg_us(&self) -> Option<f64> {
+        (self.received > 0).then(|| self.rtt_sum_us / self.received as f64)
+    }
+
+    /// Mean deviation (mdev) in microseconds: sqrt(mean(rtt^2) - mean(rtt)^2).
+    pub fn rtt_mdev_us(&self) -> Option<f64> {
+        let avg = self.rtt_avg_us()?;
+        let mean_sq = self.rtt_sum_sq_us / self.received as f64;
+        Some((mean_sq - avg * avg).max(0.0).sqrt())
+    }
+}
+
+/// Prints the trailing summary line for a host, in the same comma-separated
+/// style as the per-packet rows `Pinger::ping` prints while the sweep runs.
+pub fn print_summary(addr: impl std::fmt::Display, stats: &PingStats) {
+    println!(
+        "{},summary,{}/{},{:.2}%,{},{},{},{:.2},{}errs",
+        addr,
+        stats.received,
+        stats.transmitted,
+        stats.loss_pct(),
+        stats.rtt_min_us.unwrap_or(0),
+        stats.rtt_avg_us().unwrap_or(0.0),
+        stats.rtt_max_us.unwrap_or(0),
+        stats.rtt_mdev_us().unwrap_or(0.0),
+        stats.recv_errors
+    );
+}
+
+/// Largest span [`Pinger::ping_v4`]/[`Pinger::ping_v6`] ever block on a
+/// single `next_with_timeout` call for, so the loop still wakes up
+/// periodically to notice a shutdown signal even when nothing else is
+/// scheduled.
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Computes how long the receive call should block for: the time remaining
+/// until the next send (if `more_to_send`) or the earliest outstanding
+/// timeout, whichever is sooner, clamped to [`MAX_POLL_INTERVAL`]. Replaces
+/// polling on a fixed short tick with a wait sized to the actual next
+/// deadline.
+fn poll_timeout(
+    more_to_send: bool,
+    next_pkt: Instant,
+    timeouts: &BinaryHeap<Reverse<(Instant, u16)>>,
+) -> Duration {
+    let now = Instant::now();
+    let earliest_timeout = timeouts.peek().map(|Reverse((t, _))| *t);
+    let deadline = match (more_to_send, earliest_timeout) {
+        (true, Some(t)) => next_pkt.min(t),
+        (true, None) => next_pkt,
+        (false, Some(t)) => t,
+        (false, None) => return MAX_POLL_INTERVAL,
+    };
+    deadline
+        .saturating_duration_since(now)
+        .min(MAX_POLL_INTERVAL)
+}
+
 /// Represents a set of inputs to run a ping program on
 pub struct Pinger<'a> {
     /// params for pinging
@@ -75,6 +219,13 @@ pub struct Pinger<'a> {
     active_requests: SafeActiveRequestMap,
     /// timeout for each echo reply
     timeout: Duration,
+    /// reference instant that request payload timestamps are relative to
+    epoch: Instant,
+    /// set by the process's signal handler on SIGINT/SIGTERM; once true, the
+    /// event loop stops scheduling new echoes but keeps draining outstanding
+    /// ones until they reply or time out, so a partial sweep still reports
+    /// everything it learned.
+    shutdown: Arc<AtomicBool>,
 }
 
 impl<'a> Pinger<'a> {
@@ -82,23 +233,159 @@ impl<'a> Pinger<'a> {
         params: &'a PingParams,
         timeout: Duration,
         active_requests: SafeActiveRequestMap,
+        shutdown: Arc<AtomicBool>,
     ) -> Self {
         Pinger {
             params,
             timeout,
             active_requests,
+            epoch: Instant::now(),
+            shutdown,
         }
     }
 
     /// Generates ICMP Echo Request/Reply packets based on the parameters within
     /// the struct.
     ///
+    /// Dispatches to the IPv4 or IPv6 implementation based on `self.params.ip`.
+    /// The IPv4 path reuses the Layer3 channel passed in by the caller; the
+    /// IPv6 path opens its own Layer3 channel for next-header 58 (ICMPv6),
+    /// since `tx`/`rx` are only valid for IPv4 traffic.
+    ///
     /// ping output is printed to stdout
     pub fn ping(
         &self,
         tx: &mut TransportSender,
         rx: &mut Ipv4TransportChannelIterator,
-    ) -> PingResult<()> {
+    ) -> PingResult<PingStats> {
+        match self.params.ip {
+            IpAddr::V4(ip) => self.ping_v4(ip, tx, rx),
+            IpAddr::V6(ip) => self.ping_v6(ip),
+        }
+    }
+
+    fn ping_v4(
+        &self,
+        addr: Ipv4Addr,
+        tx: &mut TransportSender,
+        rx: &mut Ipv4TransportChannelIterator,
+    ) -> PingResult<PingStats> {
+        self.run_sweep(
+            IpAddr::V4(addr),
+            |d| {
+                let Some((packet, ip)) = rx.next_with_timeout(d)? else {
+                    return Ok(None);
+                };
+                let ttl = packet.get_ttl() as u32;
+                let Some(reply) = EchoReplyPacket::new(packet.payload()) else {
+                    return Ok(None);
+                };
+                if reply.get_icmp_type() != pnet::packet::icmp::IcmpTypes::EchoReply {
+                    return Ok(None);
+                }
+                Ok(Some((
+                    ip,
+                    reply.get_sequence_number(),
+                    ttl,
+                    reply.payload().to_vec(),
+                )))
+            },
+            |seq, payload| IcmpEcho::with_payload(id() as u16, seq, payload),
+            |echo, seq| {
+                let mut icmp_buf = vec![0u8; echo.wire_len()];
+                echo.construct_buf(&mut icmp_buf);
+                let mut buf = vec![0u8; MutableIpv4Packet::minimum_packet_size() + icmp_buf.len()];
+                let mut pkt = MutableIpv4Packet::new(&mut buf)
+                    .unwrap_or_else(|| panic!("Couldn't create ipv4 packet"));
+                pkt.populate(&Ipv4 {
+                    version: 4,
+                    header_length: 5,
+                    dscp: 0,
+                    ecn: 0,
+                    total_length: icmp_buf.len() as u16
+                        + MutableIpv4Packet::minimum_packet_size() as u16,
+                    identification: 0,
+                    flags: 2,
+                    fragment_offset: 0,
+                    ttl: 64, // unsure if required when tx channel has TTL set
+                    next_level_protocol: IpNextHeaderProtocol(1),
+                    checksum: 0,
+                    source: Ipv4Addr::new(0, 0, 0, 0),
+                    destination: addr,
+                    options: vec![],
+                    payload: icmp_buf,
+                });
+                if let Err(e) = tx.send_to(pkt, IpAddr::V4(addr)) {
+                    eprintln!("Failed to send echo request {} to {}: {:?}", seq, addr, e);
+                }
+            },
+        )
+    }
+
+    /// IPv6 counterpart of [`Pinger::ping_v4`]. Opens its own Layer3 channel
+    /// for next-header 58 (ICMPv6) since raw ICMPv6 sockets strip the IPv6
+    /// header on receive and don't accept one on send -- the iterator yields
+    /// ICMPv6 packets directly rather than full IP packets, and `send_to`
+    /// takes the ICMPv6 message alone.
+    fn ping_v6(&self, addr: Ipv6Addr) -> PingResult<PingStats> {
+        let buf_size = 4096;
+        let proto = IpNextHeaderProtocol::new(58); // 58 for ICMPv6
+        let (mut tx, mut rx) = transport_channel(buf_size, TransportChannelType::Layer3(proto))?;
+        let mut iter = icmpv6_packet_iter(&mut rx);
+
+        self.run_sweep(
+            IpAddr::V6(addr),
+            |d| {
+                let Some((packet, ip)) = iter.next_with_timeout(d)? else {
+                    return Ok(None);
+                };
+                if packet.get_icmpv6_type() != Icmpv6Types::EchoReply {
+                    return Ok(None);
+                }
+                let Some(reply) = EchoReplyPacketV6::new(packet.packet()) else {
+                    return Ok(None);
+                };
+                // hop limit isn't exposed by the ICMPv6 Layer3 iterator
+                Ok(Some((ip, reply.get_sequence_number(), 0, reply.payload().to_vec())))
+            },
+            |seq, payload| IcmpEcho::with_payload_v6(id() as u16, seq, payload),
+            |echo, seq| {
+                let mut icmp_buf = vec![0u8; echo.wire_len()];
+                // source is left unspecified: raw ICMPv6 sockets don't expose
+                // the bound address and the kernel fills it in on the wire,
+                // so the checksum here only covers what we control.
+                echo.construct_buf_v6(&mut icmp_buf, Ipv6Addr::UNSPECIFIED, addr);
+                let pkt = Icmpv6Packet::new(&icmp_buf)
+                    .unwrap_or_else(|| panic!("Couldn't create icmpv6 packet"));
+                if let Err(e) = tx.send_to(pkt, IpAddr::V6(addr)) {
+                    eprintln!("Failed to send echo request {} to {}: {:?}", seq, addr, e);
+                }
+            },
+        )
+    }
+
+    /// Shared send/receive/timeout state machine driving both
+    /// [`Pinger::ping_v4`] and [`Pinger::ping_v6`]; the two differ only in
+    /// how a reply is read off the wire, how an outgoing [`IcmpEcho`] is
+    /// built, and how it's framed and sent, so those are the only pieces
+    /// passed in as closures.
+    ///
+    /// `recv_reply` blocks for up to the given duration and returns the
+    /// `(source address, sequence number, ttl, payload)` of the next matching
+    /// echo reply, or `None` on a timeout or a packet that isn't one (wrong
+    /// type, wrong family, unparseable). It owns the full receive-and-parse
+    /// step itself -- rather than handing back a borrowed packet for a
+    /// separate parsing step -- since the packet types `pnet`'s iterators
+    /// yield borrow from the iterator and can't be named as a generic that
+    /// outlives the closure call. `make_echo` builds the next outgoing
+    /// request, and `send` frames and transmits it.
+    fn run_sweep(
+        &self,
+        addr: IpAddr,
+        mut recv_reply: impl FnMut(Duration) -> io::Result<Option<(IpAddr, u16, u32, Vec<u8>)>>,
+        make_echo: impl Fn(u16, Vec<u8>) -> IcmpEcho,
+        mut send: impl FnMut(IcmpEcho, u16),
+    ) -> PingResult<PingStats> {
         // stores set of instants where a timeout should be recorded and entry removed
         // from the active request map
         let mut timeouts = BinaryHeap::new();
@@ -106,54 +393,54 @@ impl<'a> Pinger<'a> {
         let mut outstanding = HashSet::new();
         let mut next_pkt = Instant::now();
         let mut seq = 0;
+        let mut stats = PingStats::default();
 
         // main event loop
         loop {
-            // this could be executed with timeouts at finer granularity, but ping
-            // the requirements for ICMP echo generally don't require higher levels
-            // of precision.
-            match rx.next_with_timeout(Duration::from_millis(1)) {
-                Ok(Some((packet, addr))) => {
-                    if let IpAddr::V4(ip) = addr {
-                        let recv = Instant::now();
-                        let ttl = packet.get_ttl();
-                        let payload = packet.payload();
-                        if let Some(reply) = EchoReplyPacket::new(payload) {
-                            if self.params.ip != ip {
-                                eprintln!(
-                                    "got reply from {} for {}:{}",
-                                    ip,
-                                    self.params.ip,
-                                    reply.get_sequence_number()
-                                );
-                                // continue;
-                            }
-                            let seq = reply.get_sequence_number();
-                            let mut remove = false;
-                            let mut _guard = self.active_requests.write()?;
-                            if let Some(time) = _guard.get(&(ip, seq)) {
-                                let lat: Duration = recv - *time;
-                                remove = true;
-                                println!("{},{},{},{}", ip, ttl, seq, lat.as_micros())
-                            }
-                            outstanding.remove(&(ip, seq));
-                            if remove {
-                                _guard.remove(&(ip, seq));
-                            }
-                            drop(_guard);
-                        };
+            let sending_done = seq >= self.params.requests || self.shutdown.load(Ordering::Relaxed);
+
+            match recv_reply(poll_timeout(!sending_done, next_pkt, &timeouts)) {
+                Ok(Some((ip, rseq, ttl, payload))) => {
+                    let recv = Instant::now();
+                    if addr != ip {
+                        eprintln!("got reply from {} for {}:{}", ip, addr, rseq);
+                    }
+                    let key = (ip, rseq);
+                    let mut remove = false;
+                    let mut _guard = self.active_requests.write()?;
+                    if let Some(sent) = _guard.get(&key) {
+                        if nonce_matches(&payload, &sent.nonce) {
+                            let lat: Duration = recv - sent.time;
+                            remove = true;
+                            stats.record_reply(lat);
+                            println!("{},{},{},{}", ip, ttl, rseq, lat.as_micros())
+                        } else {
+                            eprintln!(
+                                "discarding reply from {} for seq {}: nonce mismatch",
+                                ip, rseq
+                            );
+                        }
+                    }
+                    if remove {
+                        outstanding.remove(&key);
+                        _guard.remove(&key);
                     }
+                    drop(_guard);
                 }
                 Ok(None) => (),
-                Err(e) => eprintln!("Error occurred while reading packets: {:?}", e),
+                Err(e) => {
+                    stats.record_recv_error();
+                    eprintln!("Error occurred while reading packets: {:?}", e);
+                }
             }
 
             // check if we need to break the event loop
             // finish condition is that we've sent `seq` # of requests and that
             // all outstanding requests have been printed
-            if seq >= self.params.requests {
-                // all messages sent, check if there any of the outstanding
-                // which may have been handled by another socket
+            if sending_done {
+                // all messages sent (or shutdown was requested), check if
+                // there are any outstanding which may have been handled by
+                // another socket
                 let mut rms = vec![];
                 for msg in outstanding.iter() {
                     if !self.active_requests.read()?.contains_key(msg) {
@@ -169,40 +456,12 @@ impl<'a> Pinger<'a> {
             }
 
             // check if a new request should be sent
-            if Instant::now() > next_pkt && seq < self.params.requests {
-                let mut buf =
-                    vec![0u8; MutableIpv4Packet::minimum_packet_size() + IcmpEcho::size()];
-                let mut icmp_buf = vec![0u8; IcmpEcho::size()];
-                construct_icmp_echo_request(&mut icmp_buf, seq, id() as u16);
-                let mut pkt = MutableIpv4Packet::new(&mut buf)
-                    .unwrap_or_else(|| panic!("Couldn't create ipv4 packet"));
-                pkt.populate(&Ipv4 {
-                    version: 4,
-                    header_length: 5,
-                    dscp: 0,
-                    ecn: 0,
-                    total_length: icmp_buf.len() as u16
-                        + MutableIpv4Packet::minimum_packet_size() as u16,
-                    identification: 0,
-                    flags: 2,
-                    fragment_offset: 0,
-                    ttl: 64, // unsure if required when tx channel has TTL set
-                    next_level_protocol: IpNextHeaderProtocol(1),
-                    checksum: 0,
-                    source: Ipv4Addr::new(0, 0, 0, 0),
-                    destination: self.params.ip,
-                    options: vec![],
-                    payload: icmp_buf,
-                });
-
+            if !sending_done && Instant::now() > next_pkt {
+                let (payload, nonce) = build_payload(self.epoch);
+                let echo = make_echo(seq, payload);
                 let time = Instant::now();
 
-                if let Err(e) = tx.send_to(pkt, IpAddr::V4(self.params.ip)) {
-                    eprintln!(
-                        "Failed to send echo request {} to {}: {:?}",
-                        seq, self.params.ip, e
-                    );
-                }
+                send(echo, seq);
 
                 // schedule the next request
                 next_pkt = time + Duration::from_millis(self.params.interval as u64);
@@ -210,8 +469,9 @@ impl<'a> Pinger<'a> {
                 timeouts.push(Reverse((time + self.timeout, seq)));
                 self.active_requests
                     .write()?
-                    .insert((self.params.ip, seq), time);
-                outstanding.insert((self.params.ip, seq));
+                    .insert((addr, seq), SentRequest { time, nonce });
+                outstanding.insert((addr, seq));
+                stats.record_sent();
                 seq += 1;
             }
 
@@ -220,17 +480,18 @@ impl<'a> Pinger<'a> {
                 let mut pop = false;
                 if let Some(Reverse((t, _seq))) = timeouts.peek() {
                     if Instant::now() > *t {
-                        // timeout
-                        let _ = self.active_requests.write()?.remove(&(self.params.ip, seq));
                         pop = true; // needed due to lifetime constraints on peek
                     }
                 } else {
                     break;
                 }
                 if pop {
-                    if let Some(Reverse((_, seq))) = timeouts.pop() {
-                        println!("{},-1,{},timeout exceeded", self.params.ip, seq);
-                        outstanding.remove(&(self.params.ip, seq));
+                    if let Some(Reverse((_, timed_out_seq))) = timeouts.pop() {
+                        self.active_requests
+                            .write()?
+                            .remove(&(addr, timed_out_seq));
+                        println!("{},-1,{},timeout exceeded", addr, timed_out_seq);
+                        outstanding.remove(&(addr, timed_out_seq));
                     }
                 } else {
                     break;
@@ -238,6 +499,7 @@ impl<'a> Pinger<'a> {
             }
         }
 
-        Ok(())
+        print_summary(addr, &stats);
+        Ok(stats)
     }
 }